@@ -0,0 +1,102 @@
+//! `embassy-executor` based entry point.
+//!
+//! This is an alternative to the default bare-metal entry point in [`crate::sync_rt`], enabled
+//! via the `embassy` cargo feature. Instead of a hand-rolled interrupt + `wfe()` loop backed by
+//! global `Mutex<Cell<..>>`/`Mutex<RefCell<..>>` statics, the timer cadence and the button are
+//! each their own `async` task, and the [`StateMachine`] is shared between them behind an async
+//! [`Mutex`].
+
+use core::panic::PanicInfo;
+
+use embassy_executor::Spawner;
+use embassy_stm32::exti::ExtiInput;
+use embassy_stm32::gpio::{Level, Output, Pull, Speed};
+use embassy_sync::blocking_mutex::raw::ThreadModeRawMutex;
+use embassy_sync::mutex::Mutex;
+use embassy_time::{Duration, Timer};
+use static_cell::StaticCell;
+
+#[cfg(feature = "defmt")]
+use crate::sinks::DefmtSink as Sink;
+#[cfg(not(feature = "defmt"))]
+use crate::sinks::NoopSink as Sink;
+use crate::rtc::TickTimeSource;
+use crate::state_machine::{Debouncer, StateMachine, DEFAULT_DEBOUNCE_SAMPLES};
+
+/// This entry point doesn't wire up an RTC, so the counter just tracks [`PERIOD`] ticks.
+type StateMachineImpl =
+    StateMachine<3, Output<'static>, ExtiInput<'static>, Sink, DEFAULT_DEBOUNCE_SAMPLES, TickTimeSource>;
+
+/// Timer period.
+const PERIOD: Duration = Duration::from_millis(crate::PERIOD_MS as u64);
+
+/// How often [`button_task`] samples the button.
+///
+/// Must stay well below [`PERIOD`] so the debounce window settles quickly, and is awaited with
+/// the [`StateMachine`] lock released so it never blocks [`ticker_task`].
+const BUTTON_POLL_PERIOD: Duration = Duration::from_millis(5);
+
+static STATE_MACHINE: StaticCell<Mutex<ThreadModeRawMutex, StateMachineImpl>> = StaticCell::new();
+
+#[embassy_executor::main]
+async fn main(spawner: Spawner) {
+    let p = embassy_stm32::init(Default::default());
+
+    // Setup Output LEDs
+    let l1 = Output::new(p.PB5, Level::Low, Speed::Low);
+    let l2 = Output::new(p.PB4, Level::Low, Speed::Low);
+    let l3 = Output::new(p.PB10, Level::Low, Speed::Low);
+    let leds = [l1, l2, l3];
+
+    // Setup Input Button
+    let btn = ExtiInput::new(p.PC13, p.EXTI13, Pull::Down);
+
+    let sm = STATE_MACHINE.init(Mutex::new(StateMachine::new(
+        leds,
+        btn,
+        Sink::default(),
+        TickTimeSource::default(),
+    )));
+
+    spawner.spawn(ticker_task(sm)).unwrap();
+    spawner.spawn(button_task(sm)).unwrap();
+}
+
+/// Ticks the state machine every [`PERIOD`].
+#[embassy_executor::task]
+async fn ticker_task(sm: &'static Mutex<ThreadModeRawMutex, StateMachineImpl>) {
+    loop {
+        Timer::after(PERIOD).await;
+        sm.lock().await.tick().unwrap();
+    }
+}
+
+/// Polls the button and forwards debounced level changes to the state machine.
+///
+/// Debounce state is kept locally rather than inside the [`StateMachine`] (unlike the
+/// synchronous runtime's interrupt-driven debounce): this task only ever takes the shared lock
+/// for the brief, non-blocking sample and commit below, and awaits [`BUTTON_POLL_PERIOD`] with
+/// the lock released, so it never starves [`ticker_task`]'s own `sm.lock().await`.
+#[embassy_executor::task]
+async fn button_task(sm: &'static Mutex<ThreadModeRawMutex, StateMachineImpl>) {
+    let mut debounce = Debouncer::new();
+    loop {
+        Timer::after(BUTTON_POLL_PERIOD).await;
+
+        let mut sm = sm.lock().await;
+        let level = sm.btn_is_low().unwrap();
+        if debounce.sample::<DEFAULT_DEBOUNCE_SAMPLES>(level) {
+            sm.commit_btn_level(level);
+        }
+    }
+}
+
+#[panic_handler]
+fn panic(_info: &PanicInfo) -> ! {
+    cortex_m::interrupt::disable();
+
+    #[cfg(feature = "defmt")]
+    defmt::error!("PANIC: {}", defmt::Debug2Format(_info));
+
+    loop {}
+}
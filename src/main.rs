@@ -3,186 +3,364 @@
 #![no_std]
 #![no_main]
 
+mod rtc;
+mod sinks;
 mod state_machine;
 
-use core::{
-    cell::{Cell, RefCell},
-    fmt::Write,
-    panic::PanicInfo,
-    sync::atomic::{AtomicBool, Ordering},
-};
+#[cfg(feature = "embassy")]
+mod embassy_rt;
+#[cfg(not(feature = "embassy"))]
+mod output;
 
-use cortex_m::{asm, interrupt::Mutex};
-use cortex_m_rt::entry;
-use fugit::Duration;
-use stm32f4xx_hal::{
-    self as hal,
-    gpio::{EPin, Edge, Output, PushPull, PC13},
-    interrupt,
-    pac::{TIM2, USART2},
-    prelude::*,
-    serial::Serial,
-    timer::CounterUs,
-};
+/// Links in the `defmt` global logger, which ships log frames over the debug probe's RTT
+/// channel.
+#[cfg(feature = "defmt")]
+use defmt_rtt as _;
 
-use crate::state_machine::StateMachine;
+/// Timer period, in milliseconds.
+///
+/// Shared by both the synchronous and [`embassy`](embassy_rt) entry points so that
+/// [`state_machine::TICK_COUNT`] stays in sync regardless of which is active.
+const PERIOD_MS: u32 = 500;
+
+#[cfg(not(feature = "embassy"))]
+mod sync_rt {
+    use core::{
+        cell::{Cell, RefCell},
+        fmt::Write,
+        panic::PanicInfo,
+        sync::atomic::{AtomicBool, Ordering},
+    };
+
+    use cortex_m::{asm, interrupt::Mutex};
+    use cortex_m_rt::entry;
+    use fugit::Duration;
+    #[cfg(all(feature = "ssd1306", feature = "rtc"))]
+    use embedded_hal_bus::i2c::RefCellDevice;
+    #[cfg(any(all(feature = "ssd1306", feature = "rtc"), feature = "usb"))]
+    use static_cell::StaticCell;
+    use stm32f4xx_hal::{
+        self as hal,
+        gpio::{EPin, Edge, Output, PushPull, PC13},
+        interrupt,
+        pac::{TIM2, USART2},
+        prelude::*,
+        serial::Serial,
+        timer::CounterUs,
+    };
+    #[cfg(feature = "usb")]
+    use usb_device::device::{UsbDevice, UsbDeviceBuilder, UsbVidPid};
+
+    use crate::output::Output as DebugOutput;
+    use crate::sinks::StateSink;
+    use crate::state_machine::{StateMachine, DEFAULT_DEBOUNCE_SAMPLES};
+
+    /// I2C1, shared between the SSD1306 display and DS3231 RTC when both features are enabled.
+    #[cfg(all(feature = "ssd1306", feature = "rtc"))]
+    pub(crate) type SharedI2c1 = RefCellDevice<'static, hal::i2c::I2c<hal::pac::I2C1>>;
+    #[cfg(all(feature = "ssd1306", not(feature = "rtc")))]
+    pub(crate) type SharedI2c1 = hal::i2c::I2c<hal::pac::I2C1>;
+    #[cfg(all(feature = "rtc", not(feature = "ssd1306")))]
+    pub(crate) type SharedI2c1 = hal::i2c::I2c<hal::pac::I2C1>;
+
+    #[cfg(feature = "ssd1306")]
+    pub(crate) type Sink = crate::sinks::Ssd1306Sink<SharedI2c1>;
+    #[cfg(all(not(feature = "ssd1306"), feature = "defmt"))]
+    pub(crate) type Sink = crate::sinks::DefmtSink;
+    #[cfg(all(not(feature = "ssd1306"), not(feature = "defmt")))]
+    pub(crate) type Sink = crate::sinks::UsartSink;
+
+    #[cfg(feature = "rtc")]
+    pub(crate) type TimeImpl = crate::rtc::Ds3231<SharedI2c1>;
+    #[cfg(not(feature = "rtc"))]
+    pub(crate) type TimeImpl = crate::rtc::TickTimeSource;
+
+    /// Debug output transport: USB CDC-ACM if the `usb` feature is enabled, else USART2.
+    #[cfg(feature = "usb")]
+    pub(crate) type OutputImpl = crate::output::UsbOutput<'static>;
+    #[cfg(not(feature = "usb"))]
+    pub(crate) type OutputImpl = Serial<USART2>;
+
+    #[cfg(feature = "usb")]
+    pub(crate) type UsbDeviceImpl = UsbDevice<'static, hal::otg_fs::UsbBusType>;
+
+    pub(crate) type StateMachineImpl =
+        StateMachine<3, EPin<Output<PushPull>>, PC13, Sink, DEFAULT_DEBOUNCE_SAMPLES, TimeImpl>;
+
+    /// Timer period.
+    pub(crate) const PERIOD: Duration<u32, 1, 1_000> =
+        Duration::<u32, 1, 1_000>::millis(crate::PERIOD_MS);
+
+    // Global resources
+
+    /// Timer that ticks every [`PERIOD`].
+    ///
+    /// This can be a `Cell` b/c we are only moving it around, not mutating it.
+    static G_TIM: Mutex<Cell<Option<CounterUs<TIM2>>>> = Mutex::new(Cell::new(None));
+    /// Flag that is set when the timer ticks.
+    ///
+    /// Make sure to set this to `false` when you are done with it.
+    static TIM_FLAG: AtomicBool = AtomicBool::new(false);
+    /// Debug output transport: USART2, or a USB CDC-ACM endpoint behind the `usb` feature.
+    pub(crate) static OUTPUT: Mutex<RefCell<Option<OutputImpl>>> = Mutex::new(RefCell::new(None));
+    /// State machine.
+    static G_SM: Mutex<RefCell<Option<StateMachineImpl>>> = Mutex::new(RefCell::new(None));
+    /// I2C1 bus, promoted to `'static` so it can be shared between the display and RTC.
+    #[cfg(all(feature = "ssd1306", feature = "rtc"))]
+    static I2C1_BUS: StaticCell<RefCell<hal::i2c::I2c<hal::pac::I2C1>>> = StaticCell::new();
+    /// USB bus allocator backing [`OutputImpl`]'s `SerialPort`, promoted to `'static`.
+    #[cfg(feature = "usb")]
+    static USB_BUS: StaticCell<usb_device::bus::UsbBusAllocator<hal::otg_fs::UsbBusType>> =
+        StaticCell::new();
+    /// Packet buffer memory for the USB peripheral.
+    #[cfg(feature = "usb")]
+    static mut EP_MEMORY: [u32; 1024] = [0; 1024];
+    /// USB device state.
+    ///
+    /// Normally polled from the `OTG_FS` interrupt, but [`UsbOutput`](crate::output::UsbOutput)
+    /// also polls it directly while retrying a write or flush, since that can run from inside a
+    /// critical section (or the panic handler) where `OTG_FS` can't fire.
+    #[cfg(feature = "usb")]
+    pub(crate) static USB_DEVICE: Mutex<RefCell<Option<UsbDeviceImpl>>> = Mutex::new(RefCell::new(None));
+
+    #[entry]
+    fn main() -> ! {
+        let dp = hal::pac::Peripherals::take().unwrap();
+
+        // Setup clocks
+        let rss = dp.RCC.constrain();
+        let clocks = rss.cfgr.sysclk(16.MHz()).pclk1(8.MHz()).freeze();
+
+        // Get GPIO ports
+        let gpio_a = dp.GPIOA.split();
+        let gpio_b = dp.GPIOB.split();
+        let gpio_c = dp.GPIOC.split();
+
+        // Setup debug output transport: USART2, or a USB CDC-ACM endpoint behind the `usb`
+        // feature.
+        #[cfg(not(feature = "usb"))]
+        let cfg = hal::serial::Config::default().baudrate(57600.bps());
+        #[cfg(not(feature = "usb"))]
+        let mut output = dp
+            .USART2
+            .serial((gpio_a.pa2, gpio_a.pa3), cfg, &clocks)
+            .unwrap();
+        #[cfg(feature = "usb")]
+        let mut output = {
+            let usb = hal::otg_fs::USB {
+                usb_global: dp.OTG_FS_GLOBAL,
+                usb_device: dp.OTG_FS_DEVICE,
+                usb_pwrclk: dp.OTG_FS_PWRCLK,
+                pin_dm: gpio_a.pa11.into_alternate(),
+                pin_dp: gpio_a.pa12.into_alternate(),
+                hclk: clocks.hclk(),
+            };
+            #[allow(static_mut_refs)]
+            let usb_bus =
+                USB_BUS.init(hal::otg_fs::UsbBus::new(usb, unsafe { &mut EP_MEMORY }));
+
+            let serial = usbd_serial::SerialPort::new(usb_bus);
+            let usb_dev = UsbDeviceBuilder::new(usb_bus, UsbVidPid(0x16c0, 0x27dd))
+                .manufacturer("stm-embedded-test")
+                .product("stm-embedded-test debug console")
+                .serial_number("0")
+                .device_class(usbd_serial::USB_CLASS_CDC)
+                .build();
+
+            cortex_m::interrupt::free(|cs| {
+                USB_DEVICE.borrow(cs).replace(Some(usb_dev));
+            });
+
+            crate::output::UsbOutput(serial)
+        };
+
+        // Setup Output LEDs
+        let mut l1 = gpio_b.pb5.into_push_pull_output();
+        l1.set_low();
+        let mut l2 = gpio_b.pb4.into_push_pull_output();
+        l2.set_low();
+        let mut l3 = gpio_b.pb10.into_push_pull_output();
+        l3.set_low();
+        let leds = [l1.erase(), l2.erase(), l3.erase()];
+
+        // Setup Input Button
+        let mut btn = gpio_c.pc13.into_input().internal_pull_down(true);
+
+        // Setup Button interrupt
+        let mut syscfg = dp.SYSCFG.constrain();
+        let mut exti = dp.EXTI;
+        btn.make_interrupt_source(&mut syscfg);
+        btn.trigger_on_edge(&mut exti, Edge::Falling);
+        btn.enable_interrupt(&mut exti);
+        let btn_interrupt = btn.interrupt();
+
+        // Setup TIM2 Timer
+        let mut timer = dp.TIM2.counter_us(&clocks);
+        timer.start(PERIOD.convert()).unwrap();
+        timer.listen(hal::timer::Event::Update);
+
+        // Setup I2C1, if the SSD1306 display or the DS3231 RTC (or both) need it. When both are
+        // enabled they share the bus: the I2C peripheral is promoted to `'static` and handed out
+        // as a `RefCellDevice` to each.
+        #[cfg(any(feature = "ssd1306", feature = "rtc"))]
+        let i2c = dp.I2C1.i2c(
+            (gpio_a.pa8, gpio_c.pc9),
+            hal::i2c::Mode::Standard { frequency: 400.kHz() },
+            &clocks,
+        );
+
+        #[cfg(all(feature = "ssd1306", feature = "rtc"))]
+        let i2c_bus = I2C1_BUS.init(RefCell::new(i2c));
+
+        // Setup sink: an SSD1306 OLED over I2C1 if enabled, else defmt-over-RTT if enabled,
+        // else the debug output transport itself.
+        #[cfg(all(feature = "ssd1306", feature = "rtc"))]
+        let sink = crate::sinks::Ssd1306Sink::new(RefCellDevice::new(i2c_bus));
+        #[cfg(all(feature = "ssd1306", not(feature = "rtc")))]
+        let sink = crate::sinks::Ssd1306Sink::new(i2c);
+        #[cfg(all(not(feature = "ssd1306"), feature = "defmt"))]
+        let sink = crate::sinks::DefmtSink;
+        #[cfg(all(not(feature = "ssd1306"), not(feature = "defmt")))]
+        let sink = crate::sinks::UsartSink;
+
+        // Setup time source: a DS3231 RTC over I2C1 if enabled, else fall back to counting
+        // PERIOD ticks.
+        #[cfg(all(feature = "rtc", feature = "ssd1306"))]
+        let time_source = crate::rtc::Ds3231::new(RefCellDevice::new(i2c_bus));
+        #[cfg(all(feature = "rtc", not(feature = "ssd1306")))]
+        let time_source = crate::rtc::Ds3231::new(i2c);
+        #[cfg(not(feature = "rtc"))]
+        let time_source = crate::rtc::TickTimeSource::default();
+
+        let sm = StateMachine::new(leds, btn, sink, time_source);
+
+        writeln!(output, "Hello, World!").unwrap();
+
+        // Store peripherals in static Mutexes
+        cortex_m::interrupt::free(|cs| {
+            G_TIM.borrow(cs).set(Some(timer));
+            OUTPUT.borrow(cs).replace(Some(output));
+            G_SM.borrow(cs).replace(Some(sm));
+        });
 
-type StateMachineImpl = StateMachine<3, EPin<Output<PushPull>>, PC13>;
+        // Enable TIM2 & Button interrupts
+        unsafe {
+            cortex_m::peripheral::NVIC::unmask(hal::pac::Interrupt::TIM2);
 
-/// Timer period.
-const PERIOD: Duration<u32, 1, 1_000> = Duration::<u32, 1, 1_000>::millis(500);
+            // Enable EXTI15_10 interrupt
+            cortex_m::peripheral::NVIC::unmask(btn_interrupt);
 
-// Global resources
+            // Enable OTG_FS interrupt, which polls and services the USB CDC-ACM endpoint.
+            #[cfg(feature = "usb")]
+            cortex_m::peripheral::NVIC::unmask(hal::pac::Interrupt::OTG_FS);
+        }
 
-/// Timer that ticks every [`PERIOD`].
-///
-/// This can be a `Cell` b/c we are only moving it around, not mutating it.
-static G_TIM: Mutex<Cell<Option<CounterUs<TIM2>>>> = Mutex::new(Cell::new(None));
-/// Flag that is set when the timer ticks.
-///
-/// Make sure to set this to `false` when you are done with it.
-static TIM_FLAG: AtomicBool = AtomicBool::new(false);
-/// USART2 serial interface.
-static USART: Mutex<RefCell<Option<Serial<USART2>>>> = Mutex::new(RefCell::new(None));
-/// State machine.
-static G_SM: Mutex<RefCell<Option<StateMachineImpl>>> = Mutex::new(RefCell::new(None));
-
-#[entry]
-fn main() -> ! {
-    let dp = hal::pac::Peripherals::take().unwrap();
-
-    // Setup clocks
-    let rss = dp.RCC.constrain();
-    let clocks = rss.cfgr.sysclk(16.MHz()).pclk1(8.MHz()).freeze();
-
-    // Get GPIO ports
-    let gpio_a = dp.GPIOA.split();
-    let gpio_b = dp.GPIOB.split();
-    let gpio_c = dp.GPIOC.split();
-
-    // Setup USART
-    let cfg = hal::serial::Config::default().baudrate(57600.bps());
-    let mut usart = dp
-        .USART2
-        .serial((gpio_a.pa2, gpio_a.pa3), cfg, &clocks)
-        .unwrap();
-
-    // Setup Output LEDs
-    let mut l1 = gpio_b.pb5.into_push_pull_output();
-    l1.set_low();
-    let mut l2 = gpio_b.pb4.into_push_pull_output();
-    l2.set_low();
-    let mut l3 = gpio_b.pb10.into_push_pull_output();
-    l3.set_low();
-    let leds = [l1.erase(), l2.erase(), l3.erase()];
-
-    // Setup Input Button
-    let mut btn = gpio_c.pc13.into_input().internal_pull_down(true);
-
-    // Setup Button interrupt
-    let mut syscfg = dp.SYSCFG.constrain();
-    let mut exti = dp.EXTI;
-    btn.make_interrupt_source(&mut syscfg);
-    btn.trigger_on_edge(&mut exti, Edge::Falling);
-    btn.enable_interrupt(&mut exti);
-    let btn_interrupt = btn.interrupt();
-
-    // Setup TIM2 Timer
-    let mut timer = dp.TIM2.counter_us(&clocks);
-    timer.start(PERIOD.convert()).unwrap();
-    timer.listen(hal::timer::Event::Update);
-
-    let sm = StateMachine::new(leds, btn);
-
-    writeln!(usart, "Hello, World!").unwrap();
-
-    // Store peripherals in static Mutexes
-    cortex_m::interrupt::free(|cs| {
-        G_TIM.borrow(cs).set(Some(timer));
-        USART.borrow(cs).replace(Some(usart));
-        G_SM.borrow(cs).replace(Some(sm));
-    });
-
-    // Enable TIM2 & Button interrupts
-    unsafe {
-        cortex_m::peripheral::NVIC::unmask(hal::pac::Interrupt::TIM2);
-
-        // Enable EXTI15_10 interrupt
-        cortex_m::peripheral::NVIC::unmask(btn_interrupt);
+        loop {
+            // Wait for interrupt flag
+            while !TIM_FLAG.load(Ordering::Relaxed) {
+                // Put processor to sleep
+                asm::wfe();
+            }
+
+            let (mut sink, state, cnt, timestamp) = cortex_m::interrupt::free(|cs| {
+                // Clear flag
+                TIM_FLAG.store(false, Ordering::Relaxed);
+
+                let mut sm = G_SM.borrow(cs).borrow_mut();
+                let sm = sm.as_mut().unwrap();
+
+                // Tick state machine
+                sm.tick(cs).unwrap();
+                sm.take_for_render().unwrap()
+            });
+
+            // Rendering can be slow (e.g. an SSD1306 flush over I2C), so it's done here with
+            // interrupts unmasked rather than inside the critical section above.
+            sink.render(state, cnt, timestamp).unwrap();
+
+            cortex_m::interrupt::free(|cs| {
+                G_SM.borrow(cs)
+                    .borrow_mut()
+                    .as_mut()
+                    .unwrap()
+                    .put_sink_back(sink);
+            });
+        }
     }
 
-    loop {
-        // Wait for interrupt flag
-        while !TIM_FLAG.load(Ordering::Relaxed) {
-            // Put processor to sleep
-            asm::wfe();
-        }
+    #[interrupt]
+    fn TIM2() {
+        static mut TIM: Option<CounterUs<TIM2>> = None;
 
+        // Move timer out of static Mutex into local static variable
+        let tim = TIM.get_or_insert_with(|| {
+            cortex_m::interrupt::free(|cs| G_TIM.borrow(cs).replace(None).unwrap())
+        });
+
+        // Set flag
+        TIM_FLAG.store(true, Ordering::Relaxed);
+
+        // Clear interrupt flag
+        let _ = tim.wait();
+    }
+
+    #[interrupt]
+    fn EXTI15_10() {
         cortex_m::interrupt::free(|cs| {
-            // Clear flag
-            TIM_FLAG.store(false, Ordering::Relaxed);
+            // Log the button press
+            #[cfg(feature = "defmt")]
+            defmt::info!("Button pressed!");
+            #[cfg(not(feature = "defmt"))]
+            OUTPUT
+                .borrow(cs)
+                .borrow_mut()
+                .as_mut()
+                .unwrap()
+                .write_str("Button pressed!\r\n")
+                .unwrap();
 
-            // Tick state machine
+            // Handle button interrupt
             G_SM.borrow(cs)
                 .borrow_mut()
                 .as_mut()
                 .unwrap()
-                .tick(cs)
+                .handle_btn_interrupt(cs)
                 .unwrap();
         });
     }
-}
-
-#[interrupt]
-fn TIM2() {
-    static mut TIM: Option<CounterUs<TIM2>> = None;
-
-    // Move timer out of static Mutex into local static variable
-    let tim = TIM.get_or_insert_with(|| {
-        cortex_m::interrupt::free(|cs| G_TIM.borrow(cs).replace(None).unwrap())
-    });
 
-    // Set flag
-    TIM_FLAG.store(true, Ordering::Relaxed);
-
-    // Clear interrupt flag
-    let _ = tim.wait();
-}
-
-#[interrupt]
-fn EXTI15_10() {
-    cortex_m::interrupt::free(|cs| {
-        // Print to USART
-        USART
-            .borrow(cs)
-            .borrow_mut()
-            .as_mut()
-            .unwrap()
-            .write_str("Button pressed!\r\n")
-            .unwrap();
-
-        // Handle button interrupt
-        G_SM.borrow(cs)
-            .borrow_mut()
-            .as_mut()
-            .unwrap()
-            .handle_btn_interrupt(cs)
-            .unwrap();
-    });
-}
+    /// Polls and services the USB CDC-ACM endpoint backing [`OutputImpl`].
+    #[cfg(feature = "usb")]
+    #[interrupt]
+    fn OTG_FS() {
+        cortex_m::interrupt::free(|cs| {
+            let mut usb_dev = USB_DEVICE.borrow(cs).borrow_mut();
+            let mut output = OUTPUT.borrow(cs).borrow_mut();
+            if let (Some(usb_dev), Some(output)) = (usb_dev.as_mut(), output.as_mut()) {
+                usb_dev.poll(&mut [&mut output.0]);
+            }
+        });
+    }
 
-#[panic_handler]
-fn panic(info: &PanicInfo) -> ! {
-    // We've panicked!
-    // Disable interrupts, to ensure we are stuck here
-    cortex_m::interrupt::disable();
+    #[panic_handler]
+    fn panic(info: &PanicInfo) -> ! {
+        // We've panicked!
+        // Disable interrupts, to ensure we are stuck here
+        cortex_m::interrupt::disable();
 
-    // Print panic message to USART
-    cortex_m::interrupt::free(|cs| {
-        if let Some(usart) = USART.borrow(cs).borrow_mut().as_mut() {
-            writeln!(usart, "PANIC: {}", info).unwrap();
-        }
-    });
+        // Log the panic message
+        #[cfg(feature = "defmt")]
+        defmt::error!("PANIC: {}", defmt::Debug2Format(info));
+        #[cfg(not(feature = "defmt"))]
+        cortex_m::interrupt::free(|cs| {
+            if let Some(output) = OUTPUT.borrow(cs).borrow_mut().as_mut() {
+                writeln!(output, "PANIC: {}", info).unwrap();
+                // CDC-ACM buffers writes until the host polls for them; without this the final
+                // message would never make it out before we spin forever below.
+                output.flush();
+            }
+        });
 
-    loop {}
+        loop {}
+    }
 }
@@ -0,0 +1,99 @@
+//! Where debug/log text output goes: USART2, or USB CDC-ACM.
+//!
+//! [`crate::sinks::UsartSink`] and [`sync_rt`](crate::sync_rt)'s panic handler only need to
+//! format and write text; this trait lets them do that without caring which transport is
+//! actually wired up, selected at build time via the `usb` cargo feature.
+
+use core::fmt::Write;
+
+/// A text output transport.
+///
+/// Extends [`core::fmt::Write`] with [`flush`](Self::flush): CDC-ACM buffers writes until the
+/// host polls the endpoint, so the panic handler needs a way to push a final message out before
+/// it parks forever.
+pub trait Output: Write {
+    /// Flushes any buffered output.
+    fn flush(&mut self);
+}
+
+#[cfg(not(feature = "usb"))]
+mod usart {
+    use stm32f4xx_hal::{pac::USART2, serial::Serial};
+
+    use super::Output;
+
+    /// USART2 writes go out over the wire immediately, so there's nothing to flush.
+    impl Output for Serial<USART2> {
+        fn flush(&mut self) {}
+    }
+}
+
+#[cfg(feature = "usb")]
+mod usb {
+    use cortex_m::interrupt;
+    use stm32f4xx_hal::otg_fs::UsbBusType;
+    use usbd_serial::SerialPort;
+
+    use super::Output;
+
+    /// USB CDC-ACM transport, built on a [`SerialPort`] from `usbd-serial`.
+    pub struct UsbOutput<'a>(pub SerialPort<'a, UsbBusType>);
+
+    /// How many times [`UsbOutput::write_str`]/[`flush`](Output::flush) poll the endpoint while
+    /// waiting for it to accept more data, before giving up.
+    ///
+    /// Normally the `OTG_FS` interrupt drains the endpoint as the host polls it, but writes made
+    /// from inside a critical section (or the panic handler, which disables interrupts for good)
+    /// can't wait on that ISR ever firing. [`UsbOutput::poll`] drives it directly instead, so
+    /// this just bounds how long we keep trying before truncating output.
+    const WRITE_RETRIES: u8 = 64;
+
+    impl UsbOutput<'_> {
+        /// Services the USB device directly, the same way the `OTG_FS` interrupt normally would.
+        ///
+        /// Safe to call from inside a critical section: it only ever takes
+        /// [`crate::sync_rt::USB_DEVICE`]'s lock for the duration of the poll.
+        fn poll(&mut self) {
+            interrupt::free(|cs| {
+                if let Some(usb_dev) = crate::sync_rt::USB_DEVICE.borrow(cs).borrow_mut().as_mut() {
+                    usb_dev.poll(&mut [&mut self.0]);
+                }
+            });
+        }
+    }
+
+    impl Write for UsbOutput<'_> {
+        fn write_str(&mut self, s: &str) -> core::fmt::Result {
+            let mut bytes = s.as_bytes();
+            let mut retries_left = WRITE_RETRIES;
+            while !bytes.is_empty() && retries_left > 0 {
+                match self.0.write(bytes) {
+                    Ok(written) if written > 0 => bytes = &bytes[written..],
+                    Ok(_) | Err(usb_device::UsbError::WouldBlock) => {
+                        self.poll();
+                        retries_left -= 1;
+                    }
+                    Err(_) => return Err(core::fmt::Error),
+                }
+            }
+            Ok(())
+        }
+    }
+
+    impl Output for UsbOutput<'_> {
+        fn flush(&mut self) {
+            let mut retries_left = WRITE_RETRIES;
+            while retries_left > 0 {
+                match self.0.flush() {
+                    Ok(()) => return,
+                    Err(_) => {
+                        self.poll();
+                        retries_left -= 1;
+                    }
+                }
+            }
+        }
+    }
+}
+#[cfg(feature = "usb")]
+pub use usb::UsbOutput;
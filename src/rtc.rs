@@ -0,0 +1,116 @@
+//! Wall-clock time sources for [`StateMachine`](crate::state_machine::StateMachine).
+//!
+//! By default the state machine just counts ticks; plugging in a [`Ds3231`] instead makes it
+//! advance on true wall-clock seconds and lets output carry a real `HH:MM:SS` timestamp.
+
+use core::fmt::{self, Debug, Display, Formatter};
+
+use crate::state_machine::TICK_COUNT;
+
+/// A wall-clock time of day, as read from a [`TimeSource`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Time {
+    pub hours: u8,
+    pub minutes: u8,
+    pub seconds: u8,
+}
+
+impl Display for Time {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{:02}:{:02}:{:02}", self.hours, self.minutes, self.seconds)
+    }
+}
+
+/// Supplies [`StateMachine`](crate::state_machine::StateMachine) with wall-clock seconds.
+pub trait TimeSource {
+    /// The error type returned when reading the time source fails.
+    type Error: Debug;
+
+    /// Called once per tick; returns `true` on the tick a new second boundary is crossed, i.e.
+    /// whenever the output counter should advance.
+    fn tick_second(&mut self) -> Result<bool, Self::Error>;
+
+    /// The current time of day, if known.
+    fn now(&mut self) -> Result<Option<Time>, Self::Error>;
+}
+
+/// Fallback time source used when no RTC is configured.
+///
+/// This reproduces the state machine's original behavior: the counter advances every
+/// [`TICK_COUNT`] ticks of the TIM2 timer, and no timestamp is ever available.
+#[derive(Debug, Default, Copy, Clone)]
+pub struct TickTimeSource {
+    ticks: u8,
+}
+
+impl TimeSource for TickTimeSource {
+    type Error = core::convert::Infallible;
+
+    fn tick_second(&mut self) -> Result<bool, Self::Error> {
+        self.ticks += 1;
+        if self.ticks >= TICK_COUNT {
+            self.ticks = 0;
+            Ok(true)
+        } else {
+            Ok(false)
+        }
+    }
+
+    fn now(&mut self) -> Result<Option<Time>, Self::Error> {
+        Ok(None)
+    }
+}
+
+/// Driver for a DS3231 real-time clock over I2C, fixed at its default address.
+pub struct Ds3231<I2C> {
+    i2c: I2C,
+    last_seconds: Option<u8>,
+}
+
+impl<I2C> Ds3231<I2C> {
+    /// The DS3231's fixed I2C address.
+    pub const ADDRESS: u8 = 0x68;
+
+    /// Creates a new driver around an already-initialized I2C bus.
+    pub const fn new(i2c: I2C) -> Self {
+        Self {
+            i2c,
+            last_seconds: None,
+        }
+    }
+}
+
+impl<I2C: embedded_hal::i2c::I2c> Ds3231<I2C> {
+    /// Reads the current time from the seconds/minutes/hours registers (`0x00..=0x02`).
+    fn read_time(&mut self) -> Result<Time, I2C::Error> {
+        let mut regs = [0u8; 3];
+        self.i2c.write_read(Self::ADDRESS, &[0x00], &mut regs)?;
+        Ok(Time {
+            seconds: bcd_to_bin(regs[0] & 0x7F),
+            minutes: bcd_to_bin(regs[1] & 0x7F),
+            // Bit 6 of the hours register selects 12/24h mode; we only support 24h.
+            hours: bcd_to_bin(regs[2] & 0x3F),
+        })
+    }
+}
+
+impl<I2C: embedded_hal::i2c::I2c> TimeSource for Ds3231<I2C> {
+    type Error = I2C::Error;
+
+    fn tick_second(&mut self) -> Result<bool, Self::Error> {
+        let seconds = self.read_time()?.seconds;
+        let crossed = self.last_seconds != Some(seconds);
+        self.last_seconds = Some(seconds);
+        Ok(crossed)
+    }
+
+    fn now(&mut self) -> Result<Option<Time>, Self::Error> {
+        self.read_time().map(Some)
+    }
+}
+
+/// Converts a binary-coded-decimal register value to a plain binary one.
+const fn bcd_to_bin(v: u8) -> u8 {
+    (v & 0x0F) + (v >> 4) * 10
+}
@@ -0,0 +1,176 @@
+//! Output backends ("sinks") for [`StateMachine`](crate::state_machine::StateMachine).
+//!
+//! A sink only needs to know how to draw a [`State`] and a counter value; it doesn't care who's
+//! driving it. This is what lets the state machine stay generic over USART text output, an
+//! SSD1306 display, or (eventually) anything else, instead of reaching into a specific
+//! peripheral directly.
+
+use core::fmt::Debug;
+
+use crate::rtc::Time;
+use crate::state_machine::State;
+
+/// Renders the state machine's current output.
+///
+/// Implementors own whatever peripheral they draw to; [`render`](Self::render) is called once per
+/// tick with the latest [`State`] and counter value (by `StateMachine::tick` under `embassy`, and
+/// by [`crate::sync_rt`]'s main loop, after the tick, otherwise).
+pub trait StateSink {
+    /// The error type returned when rendering fails.
+    type Error: Debug;
+
+    /// Renders `state` and `count` (the decimal value of the LED bit pattern), prefixed with
+    /// `timestamp` when a [`TimeSource`](crate::rtc::TimeSource) has one available.
+    fn render(&mut self, state: State, count: u8, timestamp: Option<Time>) -> Result<(), Self::Error>;
+}
+
+/// Sink that does nothing.
+///
+/// Useful as a placeholder where a [`StateSink`] is required but no output peripheral has been
+/// wired up yet.
+#[derive(Debug, Default, Copy, Clone)]
+pub struct NoopSink;
+
+impl StateSink for NoopSink {
+    type Error = core::convert::Infallible;
+
+    fn render(&mut self, _state: State, _count: u8, _timestamp: Option<Time>) -> Result<(), Self::Error> {
+        Ok(())
+    }
+}
+
+#[cfg(not(feature = "embassy"))]
+mod usart {
+    use core::fmt::Write;
+
+    use super::{State, StateSink, Time};
+
+    /// Sink that formats `state` and `count` as a line of text over [`crate::sync_rt::OUTPUT`]
+    /// (USART2, or USB CDC-ACM behind the `usb` feature).
+    ///
+    /// Holds no data of its own: the transport is owned by the global static so the button/panic
+    /// handlers can share it, so this just borrows it for the duration of a single write.
+    #[derive(Debug, Default, Copy, Clone)]
+    pub struct UsartSink;
+
+    impl StateSink for UsartSink {
+        type Error = core::fmt::Error;
+
+        fn render(&mut self, state: State, count: u8, timestamp: Option<Time>) -> Result<(), Self::Error> {
+            cortex_m::interrupt::free(|cs| {
+                let mut output = crate::sync_rt::OUTPUT.borrow(cs).borrow_mut();
+                let output = output.as_mut().unwrap();
+                if let Some(timestamp) = timestamp {
+                    write!(output, "{timestamp} | ")?;
+                }
+                writeln!(output, "cnt: {count:<2} | state: {state:<8}")
+            })
+        }
+    }
+}
+#[cfg(not(feature = "embassy"))]
+pub use usart::UsartSink;
+
+#[cfg(feature = "defmt")]
+mod defmt_sink {
+    use super::{State, StateSink, Time};
+
+    /// Sink that logs `state` and `count` via `defmt`.
+    ///
+    /// Frames are compressed and shipped over the debug probe's RTT channel with no busy-wait,
+    /// unlike [`UsartSink`](super::UsartSink) which blocks on each write to its transport.
+    #[derive(Debug, Default, Copy, Clone)]
+    pub struct DefmtSink;
+
+    impl StateSink for DefmtSink {
+        type Error = core::convert::Infallible;
+
+        fn render(&mut self, state: State, count: u8, timestamp: Option<Time>) -> Result<(), Self::Error> {
+            match timestamp {
+                Some(timestamp) => {
+                    defmt::debug!("{} | state: {} | cnt: {}", defmt::Display2Format(&timestamp), state, count);
+                }
+                None => defmt::debug!("state: {} | cnt: {}", state, count),
+            }
+            Ok(())
+        }
+    }
+}
+#[cfg(feature = "defmt")]
+pub use defmt_sink::DefmtSink;
+
+#[cfg(feature = "ssd1306")]
+mod ssd1306_sink {
+    use embedded_graphics::{
+        mono_font::{ascii::FONT_6X10, MonoTextStyle},
+        pixelcolor::BinaryColor,
+        prelude::*,
+        primitives::{PrimitiveStyle, Rectangle},
+        text::Text,
+    };
+    use ssd1306::{mode::BufferedGraphicsMode, prelude::*, Ssd1306};
+
+    use super::{State, StateSink, Time};
+
+    /// Sink that draws `state`, `count`, and a bar graphic of the LED bit pattern to an SSD1306
+    /// OLED over I2C.
+    pub struct Ssd1306Sink<I2C> {
+        display: Ssd1306<I2CInterface<I2C>, DisplaySize128x64, BufferedGraphicsMode<DisplaySize128x64>>,
+    }
+
+    impl<I2C> Ssd1306Sink<I2C>
+    where
+        I2C: embedded_hal::i2c::I2c,
+    {
+        /// Creates a new sink, initializing the display over `i2c`.
+        pub fn new(i2c: I2C) -> Self {
+            let mut display = Ssd1306::new(
+                ssd1306::I2CDisplayInterface::new(i2c),
+                DisplaySize128x64,
+                DisplayRotation::Rotate0,
+            )
+            .into_buffered_graphics_mode();
+            display.init().unwrap();
+
+            Self { display }
+        }
+    }
+
+    impl<I2C> StateSink for Ssd1306Sink<I2C>
+    where
+        I2C: embedded_hal::i2c::I2c,
+    {
+        type Error = display_interface::DisplayError;
+
+        fn render(&mut self, state: State, count: u8, timestamp: Option<Time>) -> Result<(), Self::Error> {
+            self.display.clear(BinaryColor::Off).unwrap();
+
+            let text_style = MonoTextStyle::new(&FONT_6X10, BinaryColor::On);
+            let mut line = heapless::String::<32>::new();
+            let _ = match timestamp {
+                Some(timestamp) => core::fmt::write(
+                    &mut line,
+                    format_args!("{timestamp} {state:<8} cnt: {count:<2}"),
+                ),
+                None => core::fmt::write(&mut line, format_args!("{state:<8} cnt: {count:<2}")),
+            };
+            Text::new(&line, Point::new(0, 10), text_style)
+                .draw(&mut self.display)
+                .unwrap();
+
+            // Bar graphic of the LED bit pattern: one 8px-wide filled rectangle per set bit.
+            for i in 0..3 {
+                if (count >> i) & 0x01 == 1 {
+                    Rectangle::new(Point::new(i32::from(i) * 10, 24), Size::new(8, 8))
+                        .into_styled(PrimitiveStyle::with_fill(BinaryColor::On))
+                        .draw(&mut self.display)
+                        .unwrap();
+                }
+            }
+
+            self.display.flush()
+        }
+    }
+}
+#[cfg(feature = "ssd1306")]
+pub use ssd1306_sink::Ssd1306Sink;
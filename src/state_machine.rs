@@ -1,20 +1,30 @@
 use core::fmt::{Display, Formatter};
 
 use fugit::Duration;
+#[cfg(not(feature = "embassy"))]
 use stm32f4xx_hal::{
     gpio::{ExtiPin, PinState},
     hal::digital::v2::{InputPin, OutputPin},
 };
 
-use crate::PERIOD;
+#[cfg(feature = "embassy")]
+use embedded_hal::digital::{InputPin, OutputPin, PinState};
+
+use crate::rtc::{TickTimeSource, Time, TimeSource};
+use crate::sinks::StateSink;
 
 /// How long to wait before incrementing the output counter.
 const COUNT_DURATION: Duration<u32, 1, 1_000> = Duration::<u32, 1, 1_000>::millis(500);
 /// How many timer interrupts to wait before incrementing the output counter.
 ///
-/// Set by [`COUNT_DURATION`] / [`PERIOD`]
+/// Set by [`COUNT_DURATION`] / [`PERIOD_MS`](crate::PERIOD_MS)
 #[allow(clippy::cast_possible_truncation)]
-const TICK_COUNT: u8 = (COUNT_DURATION.to_millis() / PERIOD.to_millis()) as u8;
+pub(crate) const TICK_COUNT: u8 = (COUNT_DURATION.to_millis() / crate::PERIOD_MS as u64) as u8;
+
+/// Default number of consecutive agreeing samples required to accept a new button level.
+///
+/// See [`StateMachine`]'s `DEBOUNCE_SAMPLES` generic parameter.
+pub(crate) const DEFAULT_DEBOUNCE_SAMPLES: u8 = 3;
 
 /// State machine that controls the output pins.
 ///
@@ -26,15 +36,45 @@ const TICK_COUNT: u8 = (COUNT_DURATION.to_millis() / PERIOD.to_millis()) as u8;
 /// - `BITS`: The number of output pins.
 /// - `PIN`: The type of the output pins.
 /// - `BTN`: The type of the input button pin.
-pub struct StateMachine<const BITS: usize, PIN, BTN> {
+/// - `SINK`: Where the current [`State`] and counter get rendered to.
+/// - `DEBOUNCE_SAMPLES`: How many consecutive agreeing button samples are required before a new
+///   level is accepted. Tune this up if the button is especially bouncy, or down for a snappier
+///   response.
+/// - `TIME`: Where the counter's seconds come from. Defaults to [`TickTimeSource`], which just
+///   counts [`PERIOD_MS`](crate::PERIOD_MS) ticks; plug in [`Ds3231`](crate::rtc::Ds3231) instead
+///   to advance on true wall-clock seconds and pass a real timestamp to the sink.
+pub struct StateMachine<
+    const BITS: usize,
+    PIN,
+    BTN,
+    SINK,
+    const DEBOUNCE_SAMPLES: u8 = DEFAULT_DEBOUNCE_SAMPLES,
+    TIME = TickTimeSource,
+> {
     state: State,
     pins: [PIN; BITS],
     btn: BTN,
     cnt: u8,
+    /// Where the current state gets rendered to.
+    ///
+    /// `None` only ever holds momentarily: under the synchronous runtime, the main loop
+    /// temporarily takes the sink out via [`take_for_render`](Self::take_for_render) so it can
+    /// render with interrupts unmasked, and [`put_sink_back`](Self::put_sink_back) restores it
+    /// immediately after (see [`crate::sync_rt`]).
+    sink: Option<SINK>,
+    /// Debounce window opened by the most recent button edge, if one hasn't settled yet.
+    ///
+    /// Unused under `embassy`: there, debounce state lives in `button_task`'s own local instead,
+    /// since that task samples the pin directly rather than through a tick-driven window (see
+    /// [`crate::embassy_rt`]).
+    #[cfg(not(feature = "embassy"))]
+    debounce: Option<Debouncer>,
+    time_source: TIME,
 }
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
-enum State {
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub(crate) enum State {
     Paused,
     On,
 }
@@ -48,13 +88,55 @@ impl Display for State {
     }
 }
 
-impl<const BITS: usize, PIN: OutputPin, BTN: InputPin> StateMachine<BITS, PIN, BTN>
+/// Tracks consecutive agreeing button samples while a debounce window is open.
+///
+/// Under the synchronous runtime this lives inside [`StateMachine`], opened by
+/// [`handle_btn_interrupt`](StateMachine::handle_btn_interrupt). Under `embassy` it's instead
+/// kept locally by `button_task` (see [`crate::embassy_rt`]), which samples the pin on its own
+/// faster cadence rather than on the state machine's tick.
+#[derive(Debug, Copy, Clone)]
+pub(crate) struct Debouncer {
+    last_sample: bool,
+    stable_count: u8,
+}
+
+impl Debouncer {
+    pub(crate) const fn new() -> Self {
+        Self {
+            last_sample: false,
+            stable_count: 0,
+        }
+    }
+
+    /// Records a new sample, returning `true` once `SAMPLES` consecutive samples agree.
+    ///
+    /// `stable_count` is saturating: a caller that keeps sampling a level that's already settled
+    /// (as `button_task` does, forever, between edges) must not overflow it.
+    pub(crate) fn sample<const SAMPLES: u8>(&mut self, level: bool) -> bool {
+        if level == self.last_sample {
+            self.stable_count = self.stable_count.saturating_add(1);
+        } else {
+            self.last_sample = level;
+            self.stable_count = 1;
+        }
+
+        self.stable_count >= SAMPLES
+    }
+}
+
+impl<
+    const BITS: usize,
+    PIN: OutputPin,
+    BTN: InputPin,
+    SINK: StateSink,
+    const DEBOUNCE_SAMPLES: u8,
+    TIME: TimeSource,
+> StateMachine<BITS, PIN, BTN, SINK, DEBOUNCE_SAMPLES, TIME>
 where
     PIN::Error: core::fmt::Debug,
     BTN::Error: core::fmt::Debug,
 {
     const MAX_COUNT: u8 = (1 << BITS) - 1;
-    const MAX_TICK: u8 = (Self::MAX_COUNT + 1) * TICK_COUNT - 1;
 
     /// Creates a new state machine.
     ///
@@ -62,68 +144,49 @@ where
     ///
     /// * `pins`: The output pins.
     /// * `btn`: The input button pin.
+    /// * `sink`: Where the current state and counter are rendered to.
+    /// * `time_source`: Where the counter's seconds come from.
     ///
-    /// returns: `StateMachine<{ BITS }, PIN, BTN>`
-    pub const fn new(pins: [PIN; BITS], btn: BTN) -> Self {
+    /// returns: `StateMachine<{ BITS }, PIN, BTN, SINK, { DEBOUNCE_SAMPLES }, TIME>`
+    pub const fn new(pins: [PIN; BITS], btn: BTN, sink: SINK, time_source: TIME) -> Self {
         Self {
             state: State::Paused,
             pins,
             btn,
             cnt: 0,
+            sink: Some(sink),
+            #[cfg(not(feature = "embassy"))]
+            debounce: None,
+            time_source,
         }
     }
 
-    /// Ticks the state machine.
-    ///
-    /// This function requires a critical section to ensure no interrupts are fired during the
-    /// processing of the tick.
+    /// Advances the state machine by one tick.
     ///
-    /// # Arguments
-    ///
-    /// * `cs`: The critical section from [`cortex_m::interrupt::free`].
-    pub fn tick(
-        &mut self,
-        cs: &cortex_m::interrupt::CriticalSection,
-    ) -> Result<(), StateMachineError<PIN, BTN>> {
-        let btn = self.btn.is_low().map_err(StateMachineError::ButtonError)?;
-
-        // Transitions
+    /// The output counter only advances once [`TIME`](TimeSource) reports that a second has
+    /// elapsed, rather than on every tick.
+    fn transition(&mut self, btn: bool) -> Result<(), TIME::Error> {
         self.state = match self.state {
             State::Paused if !btn => State::On,
             State::Paused => State::Paused,
             State::On if btn => State::Paused,
             State::On => {
-                self.cnt = if self.cnt == Self::MAX_TICK {
-                    0
-                } else {
-                    self.cnt + 1
-                };
+                if self.time_source.tick_second()? {
+                    self.cnt = if self.cnt == Self::MAX_COUNT {
+                        0
+                    } else {
+                        self.cnt + 1
+                    };
+                }
                 State::On
             }
         };
-
-        // Actions
-        self.actions().map_err(StateMachineError::PinError)?;
-
-        // #[cfg(debug_assertions)]
-        {
-            use core::fmt::Write;
-            let mut usart = crate::USART.borrow(cs).borrow_mut();
-            let usart = usart.as_mut().unwrap();
-            writeln!(
-                usart,
-                "btn: {:<5} | cnt: {:<2} | state: {:<8}",
-                btn, self.cnt, self.state
-            )
-            .unwrap();
-        }
-
         Ok(())
     }
 
     fn actions(&mut self) -> Result<(), PIN::Error> {
         match self.state {
-            State::Paused | State::On => self.set_pins(self.cnt / TICK_COUNT),
+            State::Paused | State::On => self.set_pins(self.cnt),
         }
     }
 
@@ -134,48 +197,170 @@ where
         }
         Ok(())
     }
+
+    /// Renders the current state. Only called where the sink is guaranteed present.
+    #[cfg(feature = "embassy")]
+    fn render(&mut self, timestamp: Option<Time>) -> Result<(), SINK::Error> {
+        self.sink.as_mut().unwrap().render(self.state, self.cnt, timestamp)
+    }
+
+    /// Samples the current button level.
+    pub(crate) fn btn_is_low(&mut self) -> Result<bool, BTN::Error> {
+        self.btn.is_low()
+    }
+
+    /// Applies a debounced button level directly, without waiting for the next tick.
+    ///
+    /// Pauses on a low (pressed) level; resumes (or stays on) on a high (released) level.
+    pub(crate) fn commit_btn_level(&mut self, btn: bool) {
+        self.state = if btn {
+            State::Paused
+        } else {
+            match self.state {
+                State::Paused => State::On,
+                s @ State::On => s,
+            }
+        };
+    }
 }
 
-impl<const BITS: usize, PIN, BTN> StateMachine<BITS, PIN, BTN>
+#[cfg(not(feature = "embassy"))]
+impl<const BITS: usize, PIN, BTN, SINK, const DEBOUNCE_SAMPLES: u8, TIME>
+    StateMachine<BITS, PIN, BTN, SINK, DEBOUNCE_SAMPLES, TIME>
 where
     PIN: OutputPin,
     PIN::Error: core::fmt::Debug,
     BTN: InputPin + ExtiPin,
     BTN::Error: core::fmt::Debug,
+    SINK: StateSink,
+    TIME: TimeSource,
 {
-    /// Handles the button interrupt.
+    /// Ticks the state machine.
     ///
     /// This function requires a critical section to ensure no interrupts are fired during the
-    /// handling.
+    /// processing of the tick. While a debounce window opened by
+    /// [`handle_btn_interrupt`](Self::handle_btn_interrupt) is active, this also takes a button
+    /// sample and commits the transition once `DEBOUNCE_SAMPLES` consecutive samples agree,
+    /// instead of running the normal per-tick transition.
+    ///
+    /// This does *not* render: rendering can be arbitrarily slow (e.g. an SSD1306's I2C flush),
+    /// so [`crate::sync_rt`]'s main loop does it separately via
+    /// [`take_for_render`](Self::take_for_render), with interrupts unmasked.
+    ///
+    /// Note that while a debounce window is open, [`transition`](Self::transition) (and so
+    /// [`TIME`](TimeSource)'s second-counting and the output counter) doesn't run at all, since
+    /// samples are taken on this same per-[`PERIOD`](crate::PERIOD_MS) tick rather than a
+    /// dedicated faster timer. This means the counter can visibly stall for up to
+    /// `DEBOUNCE_SAMPLES * PERIOD` after *any* button edge, not just a bouncy one. A separate,
+    /// faster debounce-sampling timer would avoid this, at the cost of another configured
+    /// peripheral; given `DEBOUNCE_SAMPLES` defaults to a handful of ticks, the stall is short
+    /// enough that we've left it as-is.
     ///
     /// # Arguments
     ///
     /// * `_cs`: The critical section from [`cortex_m::interrupt::free`].
-    pub fn handle_btn_interrupt(
+    pub fn tick(
         &mut self,
         _cs: &cortex_m::interrupt::CriticalSection,
-    ) -> Result<(), StateMachineError<PIN, BTN>> {
+    ) -> Result<(), StateMachineError<PIN, BTN, SINK, TIME>> {
         let btn = self.btn.is_low().map_err(StateMachineError::ButtonError)?;
 
-        self.state = if btn {
-            State::Paused
-        } else {
-            match self.state {
-                State::Paused => State::On,
-                s @ State::On => s,
+        if let Some(debounce) = &mut self.debounce {
+            if debounce.sample::<DEBOUNCE_SAMPLES>(btn) {
+                self.commit_btn_level(btn);
+                self.debounce = None;
+
+                // The level has settled: drop any bounce edges latched while masked, then listen
+                // for the next edge again.
+                self.btn.clear_interrupt_pending_bit();
+                cortex_m::peripheral::NVIC::unpend(self.btn.interrupt());
+                unsafe { cortex_m::peripheral::NVIC::unmask(self.btn.interrupt()) };
             }
-        };
+        } else {
+            self.transition(btn).map_err(StateMachineError::TimeError)?;
+        }
 
         self.actions().map_err(StateMachineError::PinError)?;
 
+        Ok(())
+    }
+
+    /// Takes the sink out, along with a snapshot of what it needs to render.
+    ///
+    /// Nothing reachable from an interrupt touches the sink or reads [`TIME`](TimeSource)'s
+    /// clock, so unlike [`tick`](Self::tick) this doesn't need a critical section; the caller is
+    /// expected to render the returned sink with interrupts unmasked, then hand it back via
+    /// [`put_sink_back`](Self::put_sink_back) before the next tick.
+    pub fn take_for_render(&mut self) -> Result<(SINK, State, u8, Option<Time>), StateMachineError<PIN, BTN, SINK, TIME>> {
+        let timestamp = self.time_source.now().map_err(StateMachineError::TimeError)?;
+        let sink = self.sink.take().expect("sink already taken; put_sink_back wasn't called");
+        Ok((sink, self.state, self.cnt, timestamp))
+    }
+
+    /// Restores a sink previously removed by [`take_for_render`](Self::take_for_render).
+    pub fn put_sink_back(&mut self, sink: SINK) {
+        self.sink = Some(sink);
+    }
+
+    /// Handles the button interrupt.
+    ///
+    /// Rather than acting on the raw pin level immediately, this masks further button
+    /// interrupts and opens a debounce window; [`tick`](Self::tick) samples the pin every
+    /// [`PERIOD`](crate::PERIOD_MS) afterwards and only commits the transition (and re-enables
+    /// the interrupt) once the level has been stable for `DEBOUNCE_SAMPLES` consecutive samples.
+    ///
+    /// This function requires a critical section to ensure no interrupts are fired during the
+    /// handling.
+    ///
+    /// # Arguments
+    ///
+    /// * `_cs`: The critical section from [`cortex_m::interrupt::free`].
+    pub fn handle_btn_interrupt(
+        &mut self,
+        _cs: &cortex_m::interrupt::CriticalSection,
+    ) -> Result<(), StateMachineError<PIN, BTN, SINK, TIME>> {
+        // Mask further button interrupts until the new level has been confirmed stable.
+        unsafe { cortex_m::peripheral::NVIC::mask(self.btn.interrupt()) };
         self.btn.clear_interrupt_pending_bit();
 
+        self.debounce = Some(Debouncer::new());
+
+        Ok(())
+    }
+}
+
+#[cfg(feature = "embassy")]
+impl<const BITS: usize, PIN, BTN, SINK, const DEBOUNCE_SAMPLES: u8, TIME>
+    StateMachine<BITS, PIN, BTN, SINK, DEBOUNCE_SAMPLES, TIME>
+where
+    PIN: OutputPin,
+    PIN::Error: core::fmt::Debug,
+    BTN: InputPin,
+    BTN::Error: core::fmt::Debug,
+    SINK: StateSink,
+    TIME: TimeSource,
+{
+    /// Ticks the state machine.
+    ///
+    /// Intended to be called from an `embassy` task on every [`Timer::after`](embassy_time::Timer::after)
+    /// wakeup, so unlike the synchronous version it needs no critical section: the state machine
+    /// is shared between tasks behind its own `async` mutex instead of a global static.
+    pub fn tick(&mut self) -> Result<(), StateMachineError<PIN, BTN, SINK, TIME>> {
+        let btn = self.btn.is_low().map_err(StateMachineError::ButtonError)?;
+
+        self.transition(btn).map_err(StateMachineError::TimeError)?;
+        self.actions().map_err(StateMachineError::PinError)?;
+        let timestamp = self.time_source.now().map_err(StateMachineError::TimeError)?;
+        self.render(timestamp).map_err(StateMachineError::SinkError)?;
+
         Ok(())
     }
 }
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
-pub enum StateMachineError<PIN: OutputPin, BTN: InputPin> {
+pub enum StateMachineError<PIN: OutputPin, BTN: InputPin, SINK: StateSink, TIME: TimeSource> {
     PinError(PIN::Error),
     ButtonError(BTN::Error),
+    SinkError(SINK::Error),
+    TimeError(TIME::Error),
 }